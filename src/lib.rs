@@ -1,10 +1,154 @@
-use minijinja::{context, Environment};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use minijinja::value::Value;
+use minijinja::{context, Environment, Error as MinijinjaError, ErrorKind};
 use serde::Serialize;
 
 #[derive(Serialize, Debug)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    pub content: Content,
+    /// the function/tool calls the assistant asked to run, e.g. for an
+    /// OpenAI-style tool-calling template
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// the name of the tool this message is responding on behalf of, used
+    /// by templates that render a `tool` role message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Message {
+    /// Build a plain-text message, the common case for every built-in
+    /// template
+    pub fn new(role: impl Into<String>, content: impl Into<Content>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+            tool_calls: None,
+            name: None,
+        }
+    }
+}
+
+/// A message's content, either plain text (the common case, and the only
+/// shape the built-in templates render) or, for vision/tool-calling
+/// templates, a list of parts mirroring FastChat's `(text, [image_url])`
+/// tuple and OpenAI's multimodal `content` array.
+///
+/// `Text` serializes as a plain string, so existing templates that treat
+/// `message['content']` as a string keep working unchanged.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    pub fn text(text: impl Into<String>) -> Self {
+        Content::Text(text.into())
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.to_string())
+    }
+}
+
+/// One part of a multimodal [`Content::Parts`] message
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: String },
+}
+
+/// A tool/function call the assistant requested, mirroring the OpenAI
+/// `tool_calls` message field
+#[derive(Serialize, Debug)]
+pub struct ToolCall {
+    pub id: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FunctionCall {
+    pub name: String,
+    /// the call's arguments, JSON-encoded as a string, matching how
+    /// OpenAI-compatible APIs represent `function.arguments`
+    pub arguments: String,
+}
+
+/// Special tokens threaded into the render context, so callers can override
+/// the tokens baked into a model's `tokenizer_config.json` (e.g. Llama-3
+/// uses `<|begin_of_text|>` as its `bos_token`, not `<s>`) instead of being
+/// stuck with the defaults a built-in [`ChatTemplate`] assumes.
+#[derive(Debug, Clone)]
+pub struct SpecialTokens {
+    pub bos: String,
+    pub eos: String,
+    pub unk: Option<String>,
+    pub pad: Option<String>,
+    /// extra named tokens; these are not passed to templates, only
+    /// `bos_token`, `eos_token`, `unk_token` and `pad_token` reach the
+    /// render context
+    pub additional: HashMap<String, String>,
+}
+
+impl SpecialTokens {
+    pub fn new(bos: impl Into<String>, eos: impl Into<String>) -> Self {
+        Self {
+            bos: bos.into(),
+            eos: eos.into(),
+            unk: None,
+            pad: None,
+            additional: HashMap::new(),
+        }
+    }
+
+    /// The special tokens a built-in [`ChatTemplate`] assumes when the
+    /// caller doesn't supply its own
+    fn defaults_for(template: &ChatTemplate) -> Self {
+        match template {
+            ChatTemplate::ChatML => SpecialTokens::new("<|im_start|>", "<|im_end|>"),
+            ChatTemplate::MistralInstruct
+            | ChatTemplate::TAIDE
+            | ChatTemplate::Llama2
+            | ChatTemplate::Zephyr => SpecialTokens::new("<s>", "</s>"),
+            // https://huggingface.co/meta-llama/Meta-Llama-3-8B-Instruct/blob/main/tokenizer_config.json
+            ChatTemplate::Llama3 => SpecialTokens::new("<|begin_of_text|>", "<|eot_id|>"),
+            ChatTemplate::Gemma => SpecialTokens::new("<bos>", "<eos>"),
+            ChatTemplate::Phi3 => SpecialTokens::new("<s>", "<|endoftext|>"),
+            ChatTemplate::Falcon | ChatTemplate::ChatGLM => SpecialTokens::new("", "</s>"),
+            ChatTemplate::DeepSeek => {
+                SpecialTokens::new("<|begin_of_sentence|>", "<|end_of_sentence|>")
+            }
+            ChatTemplate::Custom(_) => SpecialTokens::new("<s>", "</s>"),
+        }
+    }
+}
+
+/// The `raise_exception` jinja global used by several HuggingFace chat
+/// templates (e.g. Mistral-Instruct) to abort rendering with a custom
+/// message, for instance when messages don't alternate `user`/`assistant`.
+/// `minijinja` has no built-in equivalent of Jinja2's `raise_exception`, so
+/// templates that call it need it registered as a function.
+fn raise_exception(msg: String) -> Result<Value, MinijinjaError> {
+    Err(MinijinjaError::new(ErrorKind::InvalidOperation, msg))
+}
+
+/// Register the globals shared by every template, e.g. [`raise_exception`]
+fn register_globals(env: &mut Environment) {
+    env.add_function("raise_exception", raise_exception);
 }
 
 /// [chatml](https://github.com/MicrosoftDocs/azure-docs/blob/main/articles/ai-services/openai/includes/chat-markup-language.md) jinja templatel, modified
@@ -14,102 +158,465 @@ const CHATML_JINJA_TEMPLATE: &str = "{% for message in messages %}{{'<|im_start|
 
 const CHATML_JINJA_TEMPLATE_NAME: &str = "chatml";
 
-const MISTRAL_INSTRUCT_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token}}{% endif %}{% endfor %}";
+/// mirrors the upstream `mistralai/Mistral-7B-Instruct` template, including
+/// its `raise_exception` guard enforcing that messages alternate
+/// `user`/`assistant`/`user`/...
+const MISTRAL_INSTRUCT_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token}}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}";
 
 const MISTRAL_INSTRUCT_TEMPLATE_NAME: &str = "mistral-instruct";
 
 const TAIDE_JINJA_TEMPLATE_NAME: &str = "taide";
 
-const TAIDE_JINJA_TEMPLATE: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = '<<SYS>>\n' + messages[0]['content'] + '\n<</SYS>>\n\n' %}{% else %}{% set loop_messages = messages %}{% set system_message = '' %}{% endif %}{% for message in loop_messages %}{% if loop.index0 == 0 %}{% set content = system_message + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content + ' [/INST]'}}{% elif message['role'] == 'assistant' %}{{ ' '  + content + ' ' + eos_token }}{% endif %}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+// unlike chatml, the llama2-derived `[INST] ... [/INST]` format has no
+// assistant header token, so `add_generation_prompt` is a no-op here
+const TAIDE_JINJA_TEMPLATE: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = '<<SYS>>\n' + messages[0]['content'] + '\n<</SYS>>\n\n' %}{% else %}{% set loop_messages = messages %}{% set system_message = '' %}{% endif %}{% for message in loop_messages %}{% if loop.index0 == 0 %}{% set content = system_message + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content + ' [/INST]'}}{% elif message['role'] == 'assistant' %}{{ ' '  + content + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+/// mirrors `meta-llama/Llama-2-7b-chat-hf`'s template
+const LLAMA2_TEMPLATE_NAME: &str = "llama2";
+
+const LLAMA2_TEMPLATE: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message %}{% set content = '<<SYS>>\n' + system_message + '\n<</SYS>>\n\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' ' + content + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+/// mirrors `meta-llama/Meta-Llama-3-8B-Instruct`'s template
+const LLAMA3_TEMPLATE_NAME: &str = "llama3";
+
+const LLAMA3_TEMPLATE: &str = "{% for message in messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n' + message['content'] | trim + eos_token %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}";
+
+/// mirrors `google/gemma-7b-it`'s template
+const GEMMA_TEMPLATE_NAME: &str = "gemma";
+
+// gemma has no system role: the real google/gemma-7b-it template rejects a
+// leading system message with raise_exception instead of rendering it
+const GEMMA_TEMPLATE: &str = "{{ bos_token }}{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if message['role'] == 'assistant' %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<start_of_turn>model\n' }}{% endif %}";
+
+/// mirrors `HuggingFaceH4/zephyr-7b-beta`'s template
+const ZEPHYR_TEMPLATE_NAME: &str = "zephyr";
+
+const ZEPHYR_TEMPLATE: &str = "{% for message in messages %}{% if message['role'] == 'user' %}{{ '<|user|>\n' + message['content'] + eos_token + '\n' }}{% elif message['role'] == 'system' %}{{ '<|system|>\n' + message['content'] + eos_token + '\n' }}{% elif message['role'] == 'assistant' %}{{ '<|assistant|>\n' + message['content'] + eos_token + '\n' }}{% endif %}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>\n' }}{% endif %}";
+
+/// mirrors `microsoft/Phi-3-mini-4k-instruct`'s template
+const PHI3_TEMPLATE_NAME: &str = "phi-3";
+
+const PHI3_TEMPLATE: &str = "{% for message in messages %}{% if message['role'] == 'system' %}{{ '<|system|>\n' + message['content'] + '<|end|>\n' }}{% elif message['role'] == 'user' %}{{ '<|user|>\n' + message['content'] + '<|end|>\n' }}{% elif message['role'] == 'assistant' %}{{ '<|assistant|>\n' + message['content'] + '<|end|>\n' }}{% endif %}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>\n' }}{% endif %}";
+
+/// mirrors `tiiuae/falcon-7b-instruct`'s template
+const FALCON_TEMPLATE_NAME: &str = "falcon";
+
+const FALCON_TEMPLATE: &str = "{% for message in messages %}{{ message['role'] + ': ' + message['content'] }}{% if not loop.last %}{{ '\n' }}{% endif %}{% endfor %}{% if add_generation_prompt %}{{ '\nassistant:' }}{% endif %}";
+
+/// mirrors `THUDM/chatglm3-6b`'s template
+const CHATGLM_TEMPLATE_NAME: &str = "chatglm";
+
+const CHATGLM_TEMPLATE: &str = "{% for message in messages %}{{ '<|' + message['role'] + '|>' + '\n' + message['content'] }}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>' }}{% endif %}";
+
+/// mirrors `deepseek-ai/deepseek-llm-7b-chat`'s template
+const DEEPSEEK_TEMPLATE_NAME: &str = "deepseek";
+
+const DEEPSEEK_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if message['role'] == 'system' %}{{ message['content'] + '\n\n' }}{% elif message['role'] == 'user' %}{{ 'User: ' + message['content'] + '\n\n' }}{% elif message['role'] == 'assistant' %}{{ 'Assistant: ' + message['content'] + eos_token }}{% endif %}{% endfor %}{% if add_generation_prompt %}{{ 'Assistant:' }}{% endif %}";
 
 /// Apply Chat Markup Language (chatml) template to messages, return the prompt
 fn apply_chatml_template(
     messages: &Vec<Message>,
     add_generation_prompt: bool,
 ) -> Result<String, ApplyChatMLTemplateError> {
-    let mut env = Environment::new();
-    env.add_template(CHATML_JINJA_TEMPLATE_NAME, CHATML_JINJA_TEMPLATE)
-        .map_err(ApplyChatMLTemplateError::AddTemplateError)?;
-    let template = env
-        .get_template(CHATML_JINJA_TEMPLATE_NAME)
-        .map_err(ApplyChatMLTemplateError::GetTemplateError)?;
-    template
-        .render(context! {
-          messages => messages,
-          add_generation_prompt => add_generation_prompt,
-        })
-        .map_err(ApplyChatMLTemplateError::RenderTemplateError)
+    shared_engine()
+        .render(
+            CHATML_JINJA_TEMPLATE_NAME,
+            messages,
+            add_generation_prompt,
+            &SpecialTokens::defaults_for(&ChatTemplate::ChatML),
+        )
+        .map_err(ApplyChatMLTemplateError::from)
 }
 
 fn apply_mistral_instruct_template(
     messages: &Vec<Message>,
     add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
 ) -> Result<String, ApplyMistralInstructTemplateError> {
+    shared_engine()
+        .render(
+            MISTRAL_INSTRUCT_TEMPLATE_NAME,
+            messages,
+            add_generation_prompt,
+            special_tokens,
+        )
+        .map_err(ApplyMistralInstructTemplateError::from)
+}
+
+/// Apply TAIDE template to messages, return the prompt
+///
+/// The taide-chat template has no assistant header token of its own, so
+/// `add_generation_prompt` is always a no-op here.
+fn apply_taide_template(
+  messages: &Vec<Message>,
+  special_tokens: &SpecialTokens,
+) -> Result<String, ApplyTAIDETemplateError> {
+  shared_engine()
+      .render(TAIDE_JINJA_TEMPLATE_NAME, messages, false, special_tokens)
+      .map_err(ApplyTAIDETemplateError::from)
+}
+
+/// Apply Llama-2 template to messages, return the prompt
+fn apply_llama2_template(
+    messages: &Vec<Message>,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyLlama2TemplateError> {
+    shared_engine()
+        .render(LLAMA2_TEMPLATE_NAME, messages, false, special_tokens)
+        .map_err(ApplyLlama2TemplateError::from)
+}
+
+/// Apply Llama-3 template to messages, return the prompt
+fn apply_llama3_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyLlama3TemplateError> {
+    shared_engine()
+        .render(LLAMA3_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyLlama3TemplateError::from)
+}
+
+/// Apply Gemma template to messages, return the prompt
+fn apply_gemma_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyGemmaTemplateError> {
+    shared_engine()
+        .render(GEMMA_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyGemmaTemplateError::from)
+}
+
+/// Apply Zephyr template to messages, return the prompt
+fn apply_zephyr_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyZephyrTemplateError> {
+    shared_engine()
+        .render(ZEPHYR_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyZephyrTemplateError::from)
+}
+
+/// Apply Phi-3 template to messages, return the prompt
+fn apply_phi3_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyPhi3TemplateError> {
+    shared_engine()
+        .render(PHI3_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyPhi3TemplateError::from)
+}
+
+/// Apply Falcon template to messages, return the prompt
+fn apply_falcon_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyFalconTemplateError> {
+    shared_engine()
+        .render(FALCON_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyFalconTemplateError::from)
+}
+
+/// Apply ChatGLM template to messages, return the prompt
+fn apply_chatglm_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyChatGLMTemplateError> {
+    shared_engine()
+        .render(CHATGLM_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyChatGLMTemplateError::from)
+}
+
+/// Apply DeepSeek template to messages, return the prompt
+fn apply_deepseek_template(
+    messages: &Vec<Message>,
+    add_generation_prompt: bool,
+    special_tokens: &SpecialTokens,
+) -> Result<String, ApplyDeepSeekTemplateError> {
+    shared_engine()
+        .render(DEEPSEEK_TEMPLATE_NAME, messages, add_generation_prompt, special_tokens)
+        .map_err(ApplyDeepSeekTemplateError::from)
+}
+
+/// Apply an arbitrary jinja chat template to messages, return the prompt
+///
+/// This is how a caller resolves a template that isn't one of the built-in
+/// [`ChatTemplate`] variants, e.g. the `chat_template` field parsed out of a
+/// HuggingFace `tokenizer_config.json`.
+fn apply_custom_template(
+    template_str: &str,
+    messages: &Vec<Message>,
+    special_tokens: &SpecialTokens,
+    add_generation_prompt: bool,
+) -> Result<String, ApplyCustomTemplateError> {
     let mut env = Environment::new();
-    env.add_template(MISTRAL_INSTRUCT_TEMPLATE_NAME, MISTRAL_INSTRUCT_TEMPLATE)
-        .map_err(ApplyMistralInstructTemplateError::AddTemplateError)?;
+    // this is the one template source this crate doesn't control (e.g. the
+    // `chat_template` field out of someone else's `tokenizer_config.json`),
+    // so always cap its render fuel the same way `EngineOptions::sandboxed`
+    // does for `TemplateEngine`
+    env.set_fuel(Some(SANDBOX_FUEL));
+    register_globals(&mut env);
+    env.add_template(CUSTOM_JINJA_TEMPLATE_NAME, template_str)
+        .map_err(ApplyCustomTemplateError::AddTemplateError)?;
     let template = env
-        .get_template(MISTRAL_INSTRUCT_TEMPLATE_NAME)
-        .map_err(ApplyMistralInstructTemplateError::GetTemplateError)?;
+        .get_template(CUSTOM_JINJA_TEMPLATE_NAME)
+        .map_err(ApplyCustomTemplateError::GetTemplateError)?;
     template
         .render(context! {
           messages => messages,
           add_generation_prompt => add_generation_prompt,
-          // https://huggingface.co/mistralai/Mistral-7B-Instruct-v0.2/blob/main/tokenizer_config.json#L31
-          bos_token => "<s>",
-          // https://huggingface.co/mistralai/Mistral-7B-Instruct-v0.2/blob/main/tokenizer_config.json#L33
-          eos_token => "</s>",
+          bos_token => special_tokens.bos.clone(),
+          eos_token => special_tokens.eos.clone(),
+          unk_token => special_tokens.unk.clone(),
+          pad_token => special_tokens.pad.clone(),
         })
-        .map_err(ApplyMistralInstructTemplateError::RenderTemplateError)
+        .map_err(ApplyCustomTemplateError::RenderTemplateError)
 }
 
-/// Apply TAIDE template to messages, return the prompt
-fn apply_taide_template(
-  messages: &Vec<Message>,
-) -> Result<String, ApplyTAIDETemplateError> {
-  let mut env = Environment::new();
-  env.add_template(TAIDE_JINJA_TEMPLATE_NAME, TAIDE_JINJA_TEMPLATE)
-      .map_err(ApplyTAIDETemplateError::AddTemplateError)?;
-  let template = env
-      .get_template(TAIDE_JINJA_TEMPLATE_NAME)
-      .map_err(ApplyTAIDETemplateError::GetTemplateError)?;
-  template
-      .render(context! {
-        messages => messages,
-        bos_token => "<s>",
-        eos_token => "</s>",
-      })
-      .map_err(ApplyTAIDETemplateError::RenderTemplateError)
-}
+const CUSTOM_JINJA_TEMPLATE_NAME: &str = "custom";
 
 /// All available templates
 pub enum ChatTemplate {
     ChatML,
     MistralInstruct,
-    TAIDE
+    TAIDE,
+    Llama2,
+    Llama3,
+    Gemma,
+    Zephyr,
+    Phi3,
+    Falcon,
+    ChatGLM,
+    DeepSeek,
+    /// A raw jinja template string, e.g. the `chat_template` field parsed out
+    /// of a HuggingFace `tokenizer_config.json`
+    Custom(String),
+}
+
+/// Whitespace handling and sandboxing posture for a [`TemplateEngine`].
+///
+/// This crate only ever registers [`raise_exception`] as a callable jinja
+/// global, so a rendered template can't reach arbitrary Rust functions
+/// regardless of this config; `sandboxed` additionally caps the engine's
+/// render fuel so a hostile template (this crate is meant to render
+/// `chat_template` strings pulled out of someone else's
+/// `tokenizer_config.json`) can't hang the process with an unbounded loop.
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    /// mirrors `jinja2.Environment(trim_blocks=...)`: strip the first
+    /// newline after a block tag
+    pub trim_blocks: bool,
+    /// mirrors `jinja2.Environment(lstrip_blocks=...)`: strip leading
+    /// whitespace from the start of a line up to a block tag
+    pub lstrip_blocks: bool,
+    /// cap the number of render operations so an untrusted template can't
+    /// loop forever
+    pub sandboxed: bool,
+}
+
+impl EngineOptions {
+    /// Mirrors the reference `transformers` Python implementation, which
+    /// compiles chat templates in an
+    /// `ImmutableSandboxedEnvironment(trim_blocks=True, lstrip_blocks=True)`,
+    /// so multi-line templates using `{%- ... -%}`-style block tags produce
+    /// byte-identical output instead of leaking surrounding whitespace
+    pub fn sandboxed() -> Self {
+        Self {
+            trim_blocks: true,
+            lstrip_blocks: true,
+            sandboxed: true,
+        }
+    }
+}
+
+/// Render fuel budget applied when [`EngineOptions::sandboxed`] is set,
+/// generous enough for any legitimate chat template, but bounded so a
+/// hostile one can't hang the process
+const SANDBOX_FUEL: u64 = 1_000_000;
+
+/// A `minijinja::Environment` with every known template parsed once, so
+/// repeated renders reuse the compiled template instead of re-lexing and
+/// re-parsing the jinja source on every call. The built-in
+/// `apply_*_template` functions render through one shared engine; only a
+/// [`ChatTemplate::Custom`] template is parsed again on each
+/// [`apply_template`] call.
+pub struct TemplateEngine {
+    env: Environment<'static>,
+}
+
+impl TemplateEngine {
+    /// Build an engine with all the built-in templates pre-registered
+    pub fn new() -> Result<Self, TemplateEngineError> {
+        Self::with_options(EngineOptions::default())
+    }
+
+    /// Build an engine with all the built-in templates pre-registered,
+    /// applying `options` (e.g. [`EngineOptions::sandboxed`]) to the
+    /// underlying `Environment`
+    pub fn with_options(options: EngineOptions) -> Result<Self, TemplateEngineError> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(options.trim_blocks);
+        env.set_lstrip_blocks(options.lstrip_blocks);
+        if options.sandboxed {
+            env.set_fuel(Some(SANDBOX_FUEL));
+        }
+        register_globals(&mut env);
+        env.add_template(CHATML_JINJA_TEMPLATE_NAME, CHATML_JINJA_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(MISTRAL_INSTRUCT_TEMPLATE_NAME, MISTRAL_INSTRUCT_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(TAIDE_JINJA_TEMPLATE_NAME, TAIDE_JINJA_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(LLAMA2_TEMPLATE_NAME, LLAMA2_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(LLAMA3_TEMPLATE_NAME, LLAMA3_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(GEMMA_TEMPLATE_NAME, GEMMA_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(ZEPHYR_TEMPLATE_NAME, ZEPHYR_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(PHI3_TEMPLATE_NAME, PHI3_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(FALCON_TEMPLATE_NAME, FALCON_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(CHATGLM_TEMPLATE_NAME, CHATGLM_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        env.add_template(DEEPSEEK_TEMPLATE_NAME, DEEPSEEK_TEMPLATE)
+            .map_err(TemplateEngineError::AddTemplateError)?;
+        Ok(Self { env })
+    }
+
+    /// Register a custom jinja template (e.g. the `chat_template` field
+    /// parsed out of a HuggingFace `tokenizer_config.json`) under `name`, so
+    /// it can later be rendered by name like a built-in template
+    pub fn register_template(
+        &mut self,
+        name: String,
+        template_str: String,
+    ) -> Result<(), TemplateEngineError> {
+        self.env
+            .add_template_owned(name, template_str)
+            .map_err(TemplateEngineError::AddTemplateError)
+    }
+
+    /// Render a previously-registered template by name, reusing the
+    /// template this engine already parsed instead of parsing it again
+    pub fn render(
+        &self,
+        name: &str,
+        messages: &Vec<Message>,
+        add_generation_prompt: bool,
+        special_tokens: &SpecialTokens,
+    ) -> Result<String, TemplateEngineError> {
+        let template = self
+            .env
+            .get_template(name)
+            .map_err(TemplateEngineError::GetTemplateError)?;
+        template
+            .render(context! {
+              messages => messages,
+              add_generation_prompt => add_generation_prompt,
+              bos_token => special_tokens.bos.clone(),
+              eos_token => special_tokens.eos.clone(),
+              unk_token => special_tokens.unk.clone(),
+              pad_token => special_tokens.pad.clone(),
+            })
+            .map_err(TemplateEngineError::RenderTemplateError)
+    }
+}
+
+impl Default for TemplateEngine {
+    fn default() -> Self {
+        Self::new().expect("built-in templates are always valid")
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TemplateEngineError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+/// The engine every built-in `apply_*_template` function renders through, so
+/// the built-in templates are parsed once per process (by
+/// [`TemplateEngine::new`]) instead of once per [`apply_template`] call.
+fn shared_engine() -> &'static TemplateEngine {
+    static ENGINE: OnceLock<TemplateEngine> = OnceLock::new();
+    ENGINE.get_or_init(|| TemplateEngine::new().expect("built-in templates are always valid"))
 }
 
 /// Apply chat template to messages, return the prompt
 ///
 /// # Arguments
 /// * `messages` - a list of messages, each message contains `role` and `content`
-/// * `add_generation_prompt` - if `true`, attach `<|im_start|>assistant\n` at the end of the prompt
+/// * `add_generation_prompt` - if `true`, attach the model's assistant-turn header at the end of the prompt;
+///   Mistral-Instruct, TAIDE and Llama-2 have no such header and ignore it
 /// * `template` - the jinja template
+/// * `special_tokens` - overrides for the `bos`/`eos`/`unk`/`pad` tokens threaded into the
+///   render context; `None` falls back to the defaults for `template` (see [`SpecialTokens::defaults_for`])
 ///
 pub fn apply_template(
     template: ChatTemplate,
     messages: &Vec<Message>,
     add_generation_prompt: bool,
+    special_tokens: Option<SpecialTokens>,
 ) -> Result<String, ApplyTemplateError> {
+    let special_tokens = special_tokens.unwrap_or_else(|| SpecialTokens::defaults_for(&template));
     match template {
         ChatTemplate::ChatML => apply_chatml_template(messages, add_generation_prompt)
             .map_err(ApplyTemplateError::ApplyChatMLTemplateError),
-        ChatTemplate::MistralInstruct => {
-            apply_mistral_instruct_template(messages, add_generation_prompt)
-                .map_err(ApplyTemplateError::ApplyMistralInstructTemplateError)
-        }
-        ChatTemplate::TAIDE => apply_taide_template(messages)
+        ChatTemplate::MistralInstruct => apply_mistral_instruct_template(
+            messages,
+            add_generation_prompt,
+            &special_tokens,
+        )
+        .map_err(ApplyTemplateError::ApplyMistralInstructTemplateError),
+        ChatTemplate::TAIDE => apply_taide_template(messages, &special_tokens)
             .map_err(ApplyTemplateError::ApplyTAIDETemplateError),
+        ChatTemplate::Llama2 => apply_llama2_template(messages, &special_tokens)
+            .map_err(ApplyTemplateError::ApplyLlama2TemplateError),
+        ChatTemplate::Llama3 => {
+            apply_llama3_template(messages, add_generation_prompt, &special_tokens)
+                .map_err(ApplyTemplateError::ApplyLlama3TemplateError)
+        }
+        ChatTemplate::Gemma => {
+            apply_gemma_template(messages, add_generation_prompt, &special_tokens)
+                .map_err(ApplyTemplateError::ApplyGemmaTemplateError)
+        }
+        ChatTemplate::Zephyr => {
+            apply_zephyr_template(messages, add_generation_prompt, &special_tokens)
+                .map_err(ApplyTemplateError::ApplyZephyrTemplateError)
+        }
+        ChatTemplate::Phi3 => apply_phi3_template(messages, add_generation_prompt, &special_tokens)
+            .map_err(ApplyTemplateError::ApplyPhi3TemplateError),
+        ChatTemplate::Falcon => {
+            apply_falcon_template(messages, add_generation_prompt, &special_tokens)
+                .map_err(ApplyTemplateError::ApplyFalconTemplateError)
+        }
+        ChatTemplate::ChatGLM => {
+            apply_chatglm_template(messages, add_generation_prompt, &special_tokens)
+                .map_err(ApplyTemplateError::ApplyChatGLMTemplateError)
+        }
+        ChatTemplate::DeepSeek => {
+            apply_deepseek_template(messages, add_generation_prompt, &special_tokens)
+                .map_err(ApplyTemplateError::ApplyDeepSeekTemplateError)
+        }
+        ChatTemplate::Custom(template_str) => apply_custom_template(
+            &template_str,
+            messages,
+            &special_tokens,
+            add_generation_prompt,
+        )
+        .map_err(ApplyTemplateError::ApplyCustomTemplateError),
     }
 }
 
@@ -123,6 +630,16 @@ pub enum ApplyChatMLTemplateError {
     RenderTemplateError(#[source] minijinja::Error),
 }
 
+impl From<TemplateEngineError> for ApplyChatMLTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ApplyMistralInstructTemplateError {
     #[error("failed to add template")]
@@ -131,6 +648,197 @@ pub enum ApplyMistralInstructTemplateError {
     GetTemplateError(#[source] minijinja::Error),
     #[error("failed to render")]
     RenderTemplateError(#[source] minijinja::Error),
+    /// the template itself called `raise_exception(...)`, e.g. because
+    /// messages didn't alternate `user`/`assistant`
+    #[error("template raised an exception: {0}")]
+    RaiseExceptionError(String),
+}
+
+impl From<TemplateEngineError> for ApplyMistralInstructTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) if e.kind() == ErrorKind::InvalidOperation => {
+                Self::RaiseExceptionError(e.detail().unwrap_or_default().to_string())
+            }
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyLlama2TemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+    /// the template itself called `raise_exception(...)`, e.g. because
+    /// messages didn't alternate `user`/`assistant`
+    #[error("template raised an exception: {0}")]
+    RaiseExceptionError(String),
+}
+
+impl From<TemplateEngineError> for ApplyLlama2TemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) if e.kind() == ErrorKind::InvalidOperation => {
+                Self::RaiseExceptionError(e.detail().unwrap_or_default().to_string())
+            }
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyLlama3TemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+impl From<TemplateEngineError> for ApplyLlama3TemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyGemmaTemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+    /// the template itself called `raise_exception(...)`, e.g. because a
+    /// leading `system` message was given (Gemma has no system role)
+    #[error("template raised an exception: {0}")]
+    RaiseExceptionError(String),
+}
+
+impl From<TemplateEngineError> for ApplyGemmaTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) if e.kind() == ErrorKind::InvalidOperation => {
+                Self::RaiseExceptionError(e.detail().unwrap_or_default().to_string())
+            }
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyZephyrTemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+impl From<TemplateEngineError> for ApplyZephyrTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyPhi3TemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+impl From<TemplateEngineError> for ApplyPhi3TemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyFalconTemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+impl From<TemplateEngineError> for ApplyFalconTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyChatGLMTemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+impl From<TemplateEngineError> for ApplyChatGLMTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyDeepSeekTemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
+
+impl From<TemplateEngineError> for ApplyDeepSeekTemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -143,6 +851,25 @@ pub enum ApplyTAIDETemplateError {
     RenderTemplateError(#[source] minijinja::Error),
 }
 
+impl From<TemplateEngineError> for ApplyTAIDETemplateError {
+    fn from(err: TemplateEngineError) -> Self {
+        match err {
+            TemplateEngineError::AddTemplateError(e) => Self::AddTemplateError(e),
+            TemplateEngineError::GetTemplateError(e) => Self::GetTemplateError(e),
+            TemplateEngineError::RenderTemplateError(e) => Self::RenderTemplateError(e),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyCustomTemplateError {
+    #[error("failed to add template")]
+    AddTemplateError(#[source] minijinja::Error),
+    #[error("failed to get template")]
+    GetTemplateError(#[source] minijinja::Error),
+    #[error("failed to render")]
+    RenderTemplateError(#[source] minijinja::Error),
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum ApplyTemplateError {
@@ -152,6 +879,24 @@ pub enum ApplyTemplateError {
     ApplyMistralInstructTemplateError(#[source] ApplyMistralInstructTemplateError),
     #[error("failed to apply taide template")]
     ApplyTAIDETemplateError(#[source] ApplyTAIDETemplateError),
+    #[error("failed to apply llama2 template")]
+    ApplyLlama2TemplateError(#[source] ApplyLlama2TemplateError),
+    #[error("failed to apply llama3 template")]
+    ApplyLlama3TemplateError(#[source] ApplyLlama3TemplateError),
+    #[error("failed to apply gemma template")]
+    ApplyGemmaTemplateError(#[source] ApplyGemmaTemplateError),
+    #[error("failed to apply zephyr template")]
+    ApplyZephyrTemplateError(#[source] ApplyZephyrTemplateError),
+    #[error("failed to apply phi-3 template")]
+    ApplyPhi3TemplateError(#[source] ApplyPhi3TemplateError),
+    #[error("failed to apply falcon template")]
+    ApplyFalconTemplateError(#[source] ApplyFalconTemplateError),
+    #[error("failed to apply chatglm template")]
+    ApplyChatGLMTemplateError(#[source] ApplyChatGLMTemplateError),
+    #[error("failed to apply deepseek template")]
+    ApplyDeepSeekTemplateError(#[source] ApplyDeepSeekTemplateError),
+    #[error("failed to apply custom template")]
+    ApplyCustomTemplateError(#[source] ApplyCustomTemplateError),
 }
 
 #[cfg(test)]
@@ -161,61 +906,40 @@ mod tests {
     #[test]
     fn test_apply_chatml_template_one_shot() {
         let messages = vec![
-          Message {
-            role: "system".to_string(),
-            content: "Assistant is an intelligent chatbot designed to help users answer their tax related questions.".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "Hello, who are you?".to_string(),
-          }
+          Message::new("system", "Assistant is an intelligent chatbot designed to help users answer their tax related questions."),
+          Message::new("user", "Hello, who are you?")
         ];
 
-        let prompt = apply_template(ChatTemplate::ChatML, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::ChatML, &messages, true, None).unwrap();
         assert_eq!(prompt, "<|im_start|>system\nAssistant is an intelligent chatbot designed to help users answer their tax related questions.<|im_end|>\n<|im_start|>user\nHello, who are you?<|im_end|>\n<|im_start|>assistant\n".to_string());
 
-        let prompt = apply_template(ChatTemplate::ChatML, &messages, false).unwrap();
+        let prompt = apply_template(ChatTemplate::ChatML, &messages, false, None).unwrap();
         assert_eq!(prompt, "<|im_start|>system\nAssistant is an intelligent chatbot designed to help users answer their tax related questions.<|im_end|>\n<|im_start|>user\nHello, who are you?<|im_end|>\n".to_string());
     }
 
     #[test]
     fn test_apply_chatml_template_few_shots() {
         let messages = vec![
-          Message {
-            role: "system".to_string(),
-            content: "Assistant is an intelligent chatbot designed to help users answer their tax related questions.".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "When do I need to file my taxes by?".to_string(),
-          },
-          Message {
-            role: "assistant".to_string(),
-            content: "In 2023, you will need to file your taxes by April 18th. The date falls after the usual April 15th deadline because April 15th falls on a Saturday in 2023.".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "How can I check the status of my tax refund?".to_string(),
-          }
+          Message::new("system", "Assistant is an intelligent chatbot designed to help users answer their tax related questions."),
+          Message::new("user", "When do I need to file my taxes by?"),
+          Message::new("assistant", "In 2023, you will need to file your taxes by April 18th. The date falls after the usual April 15th deadline because April 15th falls on a Saturday in 2023."),
+          Message::new("user", "How can I check the status of my tax refund?")
         ];
 
-        let prompt = apply_template(ChatTemplate::ChatML, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::ChatML, &messages, true, None).unwrap();
         assert_eq!(prompt, "<|im_start|>system\nAssistant is an intelligent chatbot designed to help users answer their tax related questions.<|im_end|>\n<|im_start|>user\nWhen do I need to file my taxes by?<|im_end|>\n<|im_start|>assistant\nIn 2023, you will need to file your taxes by April 18th. The date falls after the usual April 15th deadline because April 15th falls on a Saturday in 2023.<|im_end|>\n<|im_start|>user\nHow can I check the status of my tax refund?<|im_end|>\n<|im_start|>assistant\n".to_string());
 
-        let prompt = apply_template(ChatTemplate::ChatML, &messages, false).unwrap();
+        let prompt = apply_template(ChatTemplate::ChatML, &messages, false, None).unwrap();
         assert_eq!(prompt, "<|im_start|>system\nAssistant is an intelligent chatbot designed to help users answer their tax related questions.<|im_end|>\n<|im_start|>user\nWhen do I need to file my taxes by?<|im_end|>\n<|im_start|>assistant\nIn 2023, you will need to file your taxes by April 18th. The date falls after the usual April 15th deadline because April 15th falls on a Saturday in 2023.<|im_end|>\n<|im_start|>user\nHow can I check the status of my tax refund?<|im_end|>\n".to_string());
     }
 
     #[test]
     fn test_apply_mistral_instruct_template_one_shot() {
         let messages = vec![
-          Message {
-            role: "user".to_string(),
-            content: "Hello, who are you?".to_string(),
-          },
+          Message::new("user", "Hello, who are you?"),
         ];
 
-        let prompt = apply_template(ChatTemplate::MistralInstruct, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::MistralInstruct, &messages, true, None).unwrap();
         assert_eq!(prompt, "<s>[INST] Hello, who are you? [/INST]".to_string());
     }
 
@@ -223,133 +947,461 @@ mod tests {
     fn test_apply_mistral_instruct_template_few_shots() {
         // see https://huggingface.co/docs/transformers/main/chat_templating#introduction
         let messages = vec![
-          Message {
-            role: "user".to_string(),
-            content: "Hello, who are you?".to_string(),
-          },
-          Message {
-            role: "assistant".to_string(),
-            content: "I'm doing great. How can I help you today?".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "I'd like to show off how chat templating works!".to_string(),
-          },
-          Message {
-            role: "assistant".to_string(),
-            content: "Are you sure?".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "Yes!".to_string(),
-          },
+          Message::new("user", "Hello, who are you?"),
+          Message::new("assistant", "I'm doing great. How can I help you today?"),
+          Message::new("user", "I'd like to show off how chat templating works!"),
+          Message::new("assistant", "Are you sure?"),
+          Message::new("user", "Yes!"),
         ];
 
-        let prompt = apply_template(ChatTemplate::MistralInstruct, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::MistralInstruct, &messages, true, None).unwrap();
         assert_eq!(prompt, "<s>[INST] Hello, who are you? [/INST]I'm doing great. How can I help you today?</s>[INST] I'd like to show off how chat templating works! [/INST]Are you sure?</s>[INST] Yes! [/INST]".to_string());
     }
 
     #[test]
     fn test_apply_taide_template_one_shot() {
         let messages = vec![
-          Message {
-            role: "user".to_string(),
-            content: "你好嗎？".to_string(),
-          }
+          Message::new("user", "你好嗎？")
         ];
 
         // taide-chat template does not support add_generation_prompt = true
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true, None).unwrap();
         assert_eq!(prompt, "<s>[INST] 你好嗎？ [/INST]".to_string());
         
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false, None).unwrap();
         assert_eq!(prompt, "<s>[INST] 你好嗎？ [/INST]".to_string());
     }
 
     #[test]
     fn test_apply_taide_template_one_shot_with_sys_prompt() {
         let messages = vec![
-          Message {
-            role: "system".to_string(),
-            content: "你是一個來自台灣的AI助理，你的名字是 TAIDE。".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "你好嗎？".to_string(),
-          }
+          Message::new("system", "你是一個來自台灣的AI助理，你的名字是 TAIDE。"),
+          Message::new("user", "你好嗎？")
         ];
 
         // taide-chat template does not support add_generation_prompt = true
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true, None).unwrap();
         assert_eq!(prompt, "<s>[INST] <<SYS>>\n你是一個來自台灣的AI助理，你的名字是 TAIDE。\n<</SYS>>\n\n你好嗎？ [/INST]".to_string());
         
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false, None).unwrap();
         assert_eq!(prompt, "<s>[INST] <<SYS>>\n你是一個來自台灣的AI助理，你的名字是 TAIDE。\n<</SYS>>\n\n你好嗎？ [/INST]".to_string());
     }
 
     #[test]
     fn test_apply_taide_template_few_shot_with_sys_prompt() {
         let messages = vec![
-          Message {
-            role: "system".to_string(),
-            content: "你是一個來自台灣的AI助理，你的名字是 TAIDE。".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "你好嗎？".to_string(),
-          },
-          Message {
-            role: "assistant".to_string(),
-            content: "我很好。".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "今天天氣怎樣？".to_string(),
-          },
+          Message::new("system", "你是一個來自台灣的AI助理，你的名字是 TAIDE。"),
+          Message::new("user", "你好嗎？"),
+          Message::new("assistant", "我很好。"),
+          Message::new("user", "今天天氣怎樣？"),
         ];
 
         // taide-chat template does not support add_generation_prompt = true
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true, None).unwrap();
         assert_eq!(prompt, "<s>[INST] <<SYS>>\n你是一個來自台灣的AI助理，你的名字是 TAIDE。\n<</SYS>>\n\n你好嗎？ [/INST] 我很好。 </s><s>[INST] 今天天氣怎樣？ [/INST]".to_string());
         
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false, None).unwrap();
         assert_eq!(prompt, "<s>[INST] <<SYS>>\n你是一個來自台灣的AI助理，你的名字是 TAIDE。\n<</SYS>>\n\n你好嗎？ [/INST] 我很好。 </s><s>[INST] 今天天氣怎樣？ [/INST]".to_string());
     }
 
     #[test]
     fn test_apply_taide_template_few_shot_conversation_sys_prompt() {
         let messages = vec![
-          Message {
-            role: "system".to_string(),
-            content: "你是一個來自台灣的AI助理，你的名字是 TAIDE。".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "你好嗎？".to_string(),
-          },
-          Message {
-            role: "assistant".to_string(),
-            content: "我很好。".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "今天天氣怎樣？".to_string(),
-          },
-          Message {
-            role: "assistant".to_string(),
-            content: "大太陽。".to_string(),
-          },
-          Message {
-            role: "user".to_string(),
-            content: "你敢感覺如何？".to_string(),
-          },
+          Message::new("system", "你是一個來自台灣的AI助理，你的名字是 TAIDE。"),
+          Message::new("user", "你好嗎？"),
+          Message::new("assistant", "我很好。"),
+          Message::new("user", "今天天氣怎樣？"),
+          Message::new("assistant", "大太陽。"),
+          Message::new("user", "你敢感覺如何？"),
         ];
 
         // taide-chat template does not support add_generation_prompt = true
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, true, None).unwrap();
         assert_eq!(prompt, "<s>[INST] <<SYS>>\n你是一個來自台灣的AI助理，你的名字是 TAIDE。\n<</SYS>>\n\n你好嗎？ [/INST] 我很好。 </s><s>[INST] 今天天氣怎樣？ [/INST] 大太陽。 </s><s>[INST] 你敢感覺如何？ [/INST]".to_string());
         
-        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false).unwrap();
+        let prompt = apply_template(ChatTemplate::TAIDE, &messages, false, None).unwrap();
         assert_eq!(prompt, "<s>[INST] <<SYS>>\n你是一個來自台灣的AI助理，你的名字是 TAIDE。\n<</SYS>>\n\n你好嗎？ [/INST] 我很好。 </s><s>[INST] 今天天氣怎樣？ [/INST] 大太陽。 </s><s>[INST] 你敢感覺如何？ [/INST]".to_string());
     }
+
+    #[test]
+    fn test_apply_mistral_instruct_template_alternating_roles() {
+        let messages = vec![
+            Message::new("user", "Hello, who are you?"),
+            Message::new("assistant", "I'm doing great. How can I help you today?"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::MistralInstruct, &messages, true, None).unwrap();
+        assert_eq!(
+            prompt,
+            "<s>[INST] Hello, who are you? [/INST]I'm doing great. How can I help you today?</s>"
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_apply_mistral_instruct_template_non_alternating_roles() {
+        let messages = vec![
+            Message::new("user", "Hello, who are you?"),
+            Message::new("user", "Are you there?"),
+        ];
+
+        let err = apply_template(ChatTemplate::MistralInstruct, &messages, true, None).unwrap_err();
+        assert!(matches!(
+            err,
+            ApplyTemplateError::ApplyMistralInstructTemplateError(
+                ApplyMistralInstructTemplateError::RaiseExceptionError(_)
+            )
+        ));
+    }
+
+    #[test]
+    fn test_apply_llama2_template_one_shot_with_sys_prompt() {
+        let messages = vec![
+            Message::new("system", "You are a helpful assistant."),
+            Message::new("user", "Hello, who are you?"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::Llama2, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<s>[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>>\n\nHello, who are you? [/INST]".to_string());
+    }
+
+    #[test]
+    fn test_apply_llama2_template_few_shots() {
+        let messages = vec![
+            Message::new("system", "You are a helpful assistant."),
+            Message::new("user", "Hello, who are you?"),
+            Message::new("assistant", "I'm doing great. How can I help you today?"),
+            Message::new("user", "What's the weather like?"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::Llama2, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<s>[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>>\n\nHello, who are you? [/INST] I'm doing great. How can I help you today? </s><s>[INST] What's the weather like? [/INST]".to_string());
+    }
+
+    #[test]
+    fn test_apply_llama2_template_non_alternating_roles() {
+        let messages = vec![
+            Message::new("user", "Hello, who are you?"),
+            Message::new("user", "Are you there?"),
+        ];
+
+        let err = apply_template(ChatTemplate::Llama2, &messages, true, None).unwrap_err();
+        assert!(matches!(
+            err,
+            ApplyTemplateError::ApplyLlama2TemplateError(
+                ApplyLlama2TemplateError::RaiseExceptionError(_)
+            )
+        ));
+    }
+
+    #[test]
+    fn test_apply_llama3_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::Llama3, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<|begin_of_text|><|start_header_id|>user<|end_header_id|>\n\nHello, who are you?<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n".to_string());
+    }
+
+    #[test]
+    fn test_apply_llama3_template_with_overridden_special_tokens() {
+        // a caller pulling bos/eos out of a fine-tune's own tokenizer_config.json
+        // shouldn't be stuck with the defaults baked into ChatTemplate::Llama3
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let special_tokens = SpecialTokens::new("<|custom_bos|>", "<|custom_eot|>");
+        let prompt = apply_template(
+            ChatTemplate::Llama3,
+            &messages,
+            true,
+            Some(special_tokens),
+        )
+        .unwrap();
+        assert_eq!(prompt, "<|custom_bos|><|start_header_id|>user<|end_header_id|>\n\nHello, who are you?<|custom_eot|><|start_header_id|>assistant<|end_header_id|>\n\n".to_string());
+    }
+
+    #[test]
+    fn test_apply_gemma_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::Gemma, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<bos><start_of_turn>user\nHello, who are you?<end_of_turn>\n<start_of_turn>model\n".to_string());
+    }
+
+    #[test]
+    fn test_apply_gemma_template_rejects_system_message() {
+        let messages = vec![
+            Message::new("system", "You are a helpful assistant."),
+            Message::new("user", "Hello, who are you?"),
+        ];
+
+        let err = apply_template(ChatTemplate::Gemma, &messages, true, None).unwrap_err();
+        assert!(matches!(
+            err,
+            ApplyTemplateError::ApplyGemmaTemplateError(ApplyGemmaTemplateError::RaiseExceptionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_zephyr_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::Zephyr, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<|user|>\nHello, who are you?</s>\n<|assistant|>\n".to_string());
+    }
+
+    #[test]
+    fn test_apply_phi3_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::Phi3, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<|user|>\nHello, who are you?<|end|>\n<|assistant|>\n".to_string());
+    }
+
+    #[test]
+    fn test_apply_falcon_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::Falcon, &messages, true, None).unwrap();
+        assert_eq!(prompt, "user: Hello, who are you?\nassistant:".to_string());
+    }
+
+    #[test]
+    fn test_apply_falcon_template_few_shots() {
+        let messages = vec![
+            Message::new("user", "Hi"),
+            Message::new("assistant", "Hello! How can I help?"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::Falcon, &messages, false, None).unwrap();
+        assert_eq!(prompt, "user: Hi\nassistant: Hello! How can I help?".to_string());
+    }
+
+    #[test]
+    fn test_apply_falcon_template_few_shots_with_generation_prompt() {
+        let messages = vec![
+            Message::new("user", "Hi"),
+            Message::new("assistant", "Hello! How can I help?"),
+            Message::new("user", "What's the weather like?"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::Falcon, &messages, true, None).unwrap();
+        assert_eq!(
+            prompt,
+            "user: Hi\nassistant: Hello! How can I help?\nuser: What's the weather like?\nassistant:"
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_apply_chatglm_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::ChatGLM, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<|user|>\nHello, who are you?<|assistant|>".to_string());
+    }
+
+    #[test]
+    fn test_apply_chatglm_template_with_sys_prompt() {
+        let messages = vec![
+            Message::new("system", "You are ChatGLM3."),
+            Message::new("user", "Hi"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::ChatGLM, &messages, false, None).unwrap();
+        assert_eq!(prompt, "<|system|>\nYou are ChatGLM3.<|user|>\nHi".to_string());
+    }
+
+    #[test]
+    fn test_apply_deepseek_template_one_shot() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = apply_template(ChatTemplate::DeepSeek, &messages, true, None).unwrap();
+        assert_eq!(prompt, "<|begin_of_sentence|>User: Hello, who are you?\n\nAssistant:".to_string());
+    }
+
+    #[test]
+    fn test_apply_deepseek_template_with_sys_prompt() {
+        let messages = vec![
+            Message::new("system", "You are a helpful assistant."),
+            Message::new("user", "Hi"),
+            Message::new("assistant", "Hello!"),
+        ];
+
+        let prompt = apply_template(ChatTemplate::DeepSeek, &messages, false, None).unwrap();
+        assert_eq!(prompt, "<|begin_of_sentence|>You are a helpful assistant.\n\nUser: Hi\n\nAssistant: Hello!<|end_of_sentence|>".to_string());
+    }
+
+    #[test]
+    fn test_template_engine_renders_built_in_templates() {
+        let engine = TemplateEngine::new().unwrap();
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = engine
+            .render(
+                CHATML_JINJA_TEMPLATE_NAME,
+                &messages,
+                true,
+                &SpecialTokens::defaults_for(&ChatTemplate::ChatML),
+            )
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>user\nHello, who are you?<|im_end|>\n<|im_start|>assistant\n".to_string()
+        );
+
+        let prompt = engine
+            .render(
+                MISTRAL_INSTRUCT_TEMPLATE_NAME,
+                &messages,
+                true,
+                &SpecialTokens::defaults_for(&ChatTemplate::MistralInstruct),
+            )
+            .unwrap();
+        assert_eq!(prompt, "<s>[INST] Hello, who are you? [/INST]".to_string());
+    }
+
+    #[test]
+    fn test_template_engine_renders_registered_custom_template() {
+        let mut engine = TemplateEngine::new().unwrap();
+        engine
+            .register_template("my-custom-template".to_string(), CHATML_JINJA_TEMPLATE.to_string())
+            .unwrap();
+
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let prompt = engine
+            .render(
+                "my-custom-template",
+                &messages,
+                true,
+                &SpecialTokens::new("<s>", "</s>"),
+            )
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>user\nHello, who are you?<|im_end|>\n<|im_start|>assistant\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_apply_custom_template() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        // a custom template loaded at runtime, e.g. parsed out of a
+        // HuggingFace `tokenizer_config.json`'s `chat_template` field
+        let template = ChatTemplate::Custom(CHATML_JINJA_TEMPLATE.to_string());
+
+        let prompt = apply_template(template, &messages, true, None).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>user\nHello, who are you?<|im_end|>\n<|im_start|>assistant\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_apply_custom_template_invalid_syntax() {
+        let messages = vec![Message::new("user", "Hello, who are you?")];
+
+        let template = ChatTemplate::Custom("{% for message in messages %}".to_string());
+
+        let err = apply_template(template, &messages, true, None).unwrap_err();
+        assert!(matches!(
+            err,
+            ApplyTemplateError::ApplyCustomTemplateError(
+                ApplyCustomTemplateError::AddTemplateError(_)
+            )
+        ));
+    }
+
+    #[test]
+    fn test_sandboxed_engine_trims_and_lstrips_block_whitespace() {
+        let messages = vec![Message::new("user", "hi")];
+        let multi_line_template =
+            "  {% for message in messages %}\n{{ message['content'] }}\n  {% endfor %}";
+
+        let mut plain_engine = TemplateEngine::new().unwrap();
+        plain_engine
+            .register_template("multi-line".to_string(), multi_line_template.to_string())
+            .unwrap();
+        let prompt = plain_engine
+            .render(
+                "multi-line",
+                &messages,
+                false,
+                &SpecialTokens::new("<s>", "</s>"),
+            )
+            .unwrap();
+        assert_eq!(prompt, "  \nhi\n  ".to_string());
+
+        let mut sandboxed_engine = TemplateEngine::with_options(EngineOptions::sandboxed()).unwrap();
+        sandboxed_engine
+            .register_template("multi-line".to_string(), multi_line_template.to_string())
+            .unwrap();
+        let prompt = sandboxed_engine
+            .render(
+                "multi-line",
+                &messages,
+                false,
+                &SpecialTokens::new("<s>", "</s>"),
+            )
+            .unwrap();
+        assert_eq!(prompt, "hi\n".to_string());
+    }
+
+    #[test]
+    fn test_apply_custom_template_with_multimodal_content_parts() {
+        // a vision template branching on whether `content` is plain text or
+        // a list of parts, mirroring how llava-style HuggingFace templates
+        // iterate a multimodal `content` array
+        let template = "{% for message in messages %}{% if message['content'] is string %}{{ message['content'] }}{% else %}{% for part in message['content'] %}{% if part['type'] == 'text' %}{{ part['text'] }}{% elif part['type'] == 'image_url' %}{{ '[image: ' + part['image_url'] + ']' }}{% endif %}{% endfor %}{% endif %}{% endfor %}";
+
+        let messages = vec![Message {
+            content: Content::Parts(vec![
+                ContentPart::Text {
+                    text: "What's in this image?".to_string(),
+                },
+                ContentPart::ImageUrl {
+                    image_url: "https://example.com/cat.png".to_string(),
+                },
+            ]),
+            ..Message::new("user", "")
+        }];
+
+        let prompt = apply_template(
+            ChatTemplate::Custom(template.to_string()),
+            &messages,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            prompt,
+            "What's in this image?[image: https://example.com/cat.png]".to_string()
+        );
+    }
+
+    #[test]
+    fn test_apply_custom_template_with_tool_calls() {
+        let template = "{% for message in messages %}{% if message['tool_calls'] %}{% for call in message['tool_calls'] %}{{ call['function']['name'] }}({{ call['function']['arguments'] }}){% endfor %}{% endif %}{% endfor %}";
+
+        let messages = vec![Message {
+            tool_calls: Some(vec![ToolCall {
+                id: "call_1".to_string(),
+                function: FunctionCall {
+                    name: "get_weather".to_string(),
+                    arguments: "{\"city\":\"Taipei\"}".to_string(),
+                },
+            }]),
+            ..Message::new("assistant", "")
+        }];
+
+        let prompt = apply_template(
+            ChatTemplate::Custom(template.to_string()),
+            &messages,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(prompt, "get_weather({\"city\":\"Taipei\"})".to_string());
+    }
 }